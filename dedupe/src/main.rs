@@ -1,24 +1,303 @@
 #![warn(clippy::all)]
 use {
     anyhow::Error,
-    blake2::{Blake2b, Digest},
     dialoguer::{theme::ColorfulTheme, Select},
     indicatif::{ProgressBar, ProgressStyle},
     lazy_static::lazy_static,
     log::{debug, error, info, trace, warn},
     question::{Answer, Question},
+    rayon::prelude::*,
     regex::Regex,
+    serde::{Deserialize, Serialize},
     std::{
         collections::HashMap,
         convert::TryInto as _,
         fs, io,
         io::Read as _,
+        os::unix::fs::symlink,
         path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::UNIX_EPOCH,
     },
+    structopt::clap::arg_enum,
     structopt::StructOpt,
     walkdir::{DirEntry, DirEntryExt, WalkDir},
 };
 
+arg_enum! {
+    /// Hash algorithms available for dedupe comparisons
+    #[derive(Debug, Clone, Copy)]
+    enum HashType {
+        Blake3,
+        Xxh3,
+        Crc32,
+    }
+}
+
+arg_enum! {
+    /// What to do with the redundant files in a confirmed duplicate group;
+    /// the first file in the group is always kept untouched.
+    #[derive(Debug, Clone, Copy)]
+    enum Action {
+        Hardlink,
+        Reflink,
+        Symlink,
+        Delete,
+    }
+}
+
+impl Action {
+    fn verb(&self) -> &'static str {
+        match self {
+            Action::Hardlink => "hardlink",
+            Action::Reflink => "reflink",
+            Action::Symlink => "symlink",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// How confident we are that a group of same-size, same-prehash files are
+/// true duplicates, based on how well their guessed metadata agrees. Doubles
+/// as the `--confidence-threshold` CLI value and the JSON report's
+/// `confidence` field, so it round-trips as the kebab-case strings below.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum IsDuplicate {
+    VeryLikely,
+    Maybe,
+}
+
+impl IsDuplicate {
+    fn rank(&self) -> u8 {
+        match self {
+            IsDuplicate::Maybe => 1,
+            IsDuplicate::VeryLikely => 2,
+        }
+    }
+}
+
+impl std::str::FromStr for IsDuplicate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "very-likely" => Ok(IsDuplicate::VeryLikely),
+            "maybe" => Ok(IsDuplicate::Maybe),
+            other => Err(format!("Unknown confidence level {:?}, expected very-likely or maybe", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for IsDuplicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IsDuplicate::VeryLikely => write!(f, "very-likely"),
+            IsDuplicate::Maybe => write!(f, "maybe"),
+        }
+    }
+}
+
+/// Object-safe hashing interface so `generate_hash` can stream bytes into
+/// whichever algorithm `--hash-algo` selected without being generic over it.
+trait DynHasher {
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+struct Blake3Hasher(blake3::Hasher);
+impl DynHasher for Blake3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+struct Xxh3Hasher(twox_hash::Xxh3Hash64);
+impl DynHasher for Xxh3Hasher {
+    fn update(&mut self, data: &[u8]) {
+        std::hash::Hasher::write(&mut self.0, data);
+    }
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        std::hash::Hasher::finish(&self.0).to_le_bytes().to_vec()
+    }
+}
+
+struct Crc32Hasher(crc32fast::Hasher);
+impl DynHasher for Crc32Hasher {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_le_bytes().to_vec()
+    }
+}
+
+impl HashType {
+    fn hasher(&self) -> Box<dyn DynHasher> {
+        match self {
+            HashType::Blake3 => Box::new(Blake3Hasher(blake3::Hasher::new())),
+            HashType::Xxh3 => Box::new(Xxh3Hasher(twox_hash::Xxh3Hash64::default())),
+            HashType::Crc32 => Box::new(Crc32Hasher(crc32fast::Hasher::new())),
+        }
+    }
+}
+
+/// Everything we've computed for one file, keyed by its path. Invalidated
+/// wholesale the moment `size`, `mtime`, or `hash_type` stop matching the
+/// file on disk, since a hash computed under a different algorithm is
+/// meaningless to compare against one computed under this run's algorithm.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct HashCacheEntry {
+    size: u64,
+    mtime: u64,
+    hash_type: String,
+    partial_hashes: HashMap<usize, Vec<u8>>,
+    full_hash: Option<Vec<u8>>,
+}
+
+/// On-disk cache of previously computed hashes, so repeat scans of the same
+/// media library don't re-hash files that haven't changed since last run.
+/// Entries live behind a `Mutex` so lookups from the rayon hashing loops
+/// can share one cache instead of each thread keeping its own.
+struct HashCache {
+    path: PathBuf,
+    /// Keyed by canonicalized path, so a rescan through a different relative
+    /// path, CWD, or symlinked mount still hits the same entry.
+    entries: Mutex<HashMap<PathBuf, HashCacheEntry>>,
+    dirty: std::sync::atomic::AtomicBool,
+}
+
+impl HashCache {
+    fn cache_file_path() -> PathBuf {
+        dirs::cache_dir()
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("dedupe")
+            .join("hash_cache.json")
+    }
+
+    fn load() -> HashCache {
+        let path = HashCache::cache_file_path();
+        let entries = fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        HashCache {
+            path,
+            entries: Mutex::new(entries),
+            dirty: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    fn prune_missing(&mut self) {
+        let entries = self.entries.get_mut().unwrap();
+        let before = entries.len();
+        entries.retain(|path, _| path.exists());
+        if entries.len() != before {
+            debug!("Pruned {} stale cache entries", before - entries.len());
+            *self.dirty.get_mut() = true;
+        }
+    }
+
+    fn save(&self) {
+        if !self.dirty.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(parent) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                warn!("Couldn't create hash cache dir {:?}: {}", parent, e);
+                return;
+            }
+        }
+        let entries = self.entries.lock().unwrap();
+        match serde_json::to_string(&*entries) {
+            Ok(contents) => {
+                if let Err(e) = fs::write(&self.path, contents) {
+                    warn!("Couldn't write hash cache to {:?}: {}", self.path, e);
+                }
+            }
+            Err(e) => warn!("Couldn't serialize hash cache: {}", e),
+        }
+    }
+
+    fn get_partial(
+        &self,
+        path: &Path,
+        megabytes: usize,
+        size: u64,
+        mtime: u64,
+        hash_type: HashType,
+    ) -> Option<Vec<u8>> {
+        let hash_type = hash_type.to_string();
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(path)?;
+        if entry.size == size && entry.mtime == mtime && entry.hash_type == hash_type {
+            entry.partial_hashes.get(&megabytes).cloned()
+        } else {
+            None
+        }
+    }
+
+    fn put_partial(
+        &self,
+        path: &Path,
+        megabytes: usize,
+        size: u64,
+        mtime: u64,
+        hash_type: HashType,
+        hash: Vec<u8>,
+    ) {
+        let hash_type = hash_type.to_string();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(path.to_path_buf()).or_insert_with(HashCacheEntry::default);
+        if entry.size != size || entry.mtime != mtime || entry.hash_type != hash_type {
+            *entry = HashCacheEntry {
+                size,
+                mtime,
+                hash_type,
+                ..Default::default()
+            };
+        }
+        entry.partial_hashes.insert(megabytes, hash);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn get_full(&self, path: &Path, size: u64, mtime: u64, hash_type: HashType) -> Option<Vec<u8>> {
+        let hash_type = hash_type.to_string();
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(path)?;
+        if entry.size == size && entry.mtime == mtime && entry.hash_type == hash_type {
+            entry.full_hash.clone()
+        } else {
+            None
+        }
+    }
+
+    fn put_full(&self, path: &Path, size: u64, mtime: u64, hash_type: HashType, hash: Vec<u8>) {
+        let hash_type_str = hash_type.to_string();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(path.to_path_buf()).or_insert_with(HashCacheEntry::default);
+        if entry.size != size || entry.mtime != mtime || entry.hash_type != hash_type_str {
+            *entry = HashCacheEntry {
+                size,
+                mtime,
+                hash_type: hash_type_str,
+                ..Default::default()
+            };
+        }
+        entry.full_hash = Some(hash);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+}
+
+fn mtime_secs(metadata: &fs::Metadata) -> Result<u64, Error> {
+    Ok(metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "dedupe")]
 struct Opt {
@@ -31,11 +310,90 @@ struct Opt {
     #[structopt(short, long, required = true)]
     min_filesize_mb: u64,
 
+    /// Hash algorithm used when comparing file contents. xxh3 and crc32 are
+    /// much faster than blake3 but only protect against accidental
+    /// collisions, not a malicious actor crafting one on purpose.
+    #[structopt(
+        long,
+        possible_values = &HashType::variants(),
+        case_insensitive = true,
+        default_value = "Blake3"
+    )]
+    hash_algo: HashType,
+
+    /// Number of threads to hash with concurrently (defaults to the number of CPU cores)
+    #[structopt(long)]
+    threads: Option<usize>,
+
+    /// What to do with confirmed duplicates. hardlink shares one inode, so a later
+    /// in-place edit of one copy corrupts all the others; reflink makes a
+    /// copy-on-write clone (btrfs/XFS/APFS only) that stays independent; symlink
+    /// replaces the duplicate with a link to the kept copy; delete just removes it.
+    #[structopt(
+        long,
+        possible_values = &Action::variants(),
+        case_insensitive = true,
+        default_value = "Hardlink"
+    )]
+    action: Action,
+
+    /// Print discovered duplicate groups as JSON to stdout instead of prompting, and
+    /// exit without applying any action. Suitable for snapshotting a scan or feeding
+    /// the groups into another tool.
+    #[structopt(long)]
+    json: bool,
+
+    /// Skip scanning; act on the groups in a report previously saved via --json,
+    /// instead of a fresh scan of --directories. Maybe groups are re-verified with
+    /// a full-file hash before being acted on
+    #[structopt(long, parse(from_os_str), conflicts_with = "directories")]
+    from_report: Option<PathBuf>,
+
+    /// Skip interactive prompts; auto-select which groups to act on based on
+    /// --confidence-threshold instead of asking
+    #[structopt(long)]
+    non_interactive: bool,
+
+    /// Minimum confidence a group must have to be auto-selected in --non-interactive
+    /// mode (very-likely, maybe)
+    #[structopt(long, default_value = "very-likely")]
+    confidence_threshold: IsDuplicate,
+
+    /// Only consider files with one of these extensions (e.g. mkv mp4). May be
+    /// given multiple times; matched case-insensitively and without the dot
+    #[structopt(long)]
+    include_ext: Vec<String>,
+
+    /// Skip files with one of these extensions (e.g. nfo srt). May be given
+    /// multiple times; matched case-insensitively and without the dot
+    #[structopt(long)]
+    exclude_ext: Vec<String>,
+
+    /// Skip paths matching this glob (e.g. "**/Sample/**"). May be given multiple times
+    #[structopt(long)]
+    exclude_path: Vec<String>,
+
     /// Directories to process
-    #[structopt(name = "directories", parse(from_os_str), required = true)]
+    #[structopt(
+        name = "directories",
+        parse(from_os_str),
+        required_unless = "from_report"
+    )]
     directories: Vec<PathBuf>,
 }
 
+/// One duplicate group as emitted by `--json` and consumed by `--from-report`,
+/// so a saved report round-trips back into something `apply_action` can act
+/// on without repeating the scan and hash passes that produced it.
+#[derive(Serialize, Deserialize)]
+struct DuplicateGroupReport {
+    paths: Vec<PathBuf>,
+    size: u64,
+    inode: u64,
+    guessed_metadata: String,
+    confidence: IsDuplicate,
+}
+
 fn main() {
     let opt = Opt::from_args();
     stderrlog::new()
@@ -44,6 +402,29 @@ fn main() {
         .timestamp(stderrlog::Timestamp::Off)
         .init()
         .unwrap();
+    let action = opt.action;
+    let hash_type = opt.hash_algo;
+    if let Some(threads) = opt.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+    let mut hash_cache = HashCache::load();
+    hash_cache.prune_missing();
+
+    if let Some(report_path) = &opt.from_report {
+        apply_from_report(
+            report_path,
+            action,
+            opt.confidence_threshold,
+            hash_type,
+            &hash_cache,
+            opt.non_interactive,
+        );
+        hash_cache.save();
+        return;
+    }
 
     println!("Walking directories to find all filesizes");
     let spinner = ProgressBar::new_spinner();
@@ -52,10 +433,28 @@ fn main() {
             .template("[{elapsed_precise}] {spinner} {wide_msg}")
             .progress_chars("#>-"),
     );
+    let exclude_path_patterns: Vec<glob::Pattern> = opt
+        .exclude_path
+        .iter()
+        .filter_map(|pattern| match glob::Pattern::new(pattern) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                error!("Invalid --exclude-path glob {:?}: {}", pattern, e);
+                None
+            }
+        })
+        .collect();
+
     let mut filesize_map: HashMap<u64, Vec<DirEntry>> = HashMap::new();
     let mut total_file_count = 0;
     for dir in opt.directories {
-        let files = walk_directory(dir, opt.min_filesize_mb);
+        let files = walk_directory(
+            dir,
+            opt.min_filesize_mb,
+            &opt.include_ext,
+            &opt.exclude_ext,
+            &exclude_path_patterns,
+        );
         for file in files {
             spinner.set_message(format!("{}", file.path().display()));
             total_file_count += 1;
@@ -108,27 +507,71 @@ fn main() {
         duplicate_sizes.len()
     ));
 
-    let mut files_to_hardlink = vec![];
+    println!("Grouping same-size files into hash-equivalence classes");
+    let progress_bar = ProgressBar::new(duplicate_sizes.len().try_into().unwrap());
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:.cyan/blue}] {pos}/{len} ({eta}) {wide_msg}")
+            .progress_chars("#>-"),
+    );
+    let progress_count = AtomicUsize::new(0);
+    let candidate_groups: Vec<Vec<DirEntry>> = duplicate_sizes
+        .into_par_iter()
+        .flat_map(|files| {
+            progress_bar.set_position(progress_count.fetch_add(1, Ordering::SeqCst) as u64 + 1);
+            progress_bar.set_message(format!("{}", files[0].path().display()));
+            group_by_hash(files, Some(1), hash_type, &hash_cache)
+        })
+        .collect();
+    progress_bar.finish_with_message(format!(
+        "Found {} candidate duplicate groups after prehashing",
+        candidate_groups.len()
+    ));
+
+    let mut files_to_dedupe = vec![];
     let mut files_for_manual_confirmation = vec![];
 
-    println!("Examining files for duplicates");
-    let progress_bar = ProgressBar::new(duplicate_sizes.len().try_into().unwrap());
+    println!("Examining groups for duplicates");
+    let progress_bar = ProgressBar::new(candidate_groups.len().try_into().unwrap());
     progress_bar.set_style(
         ProgressStyle::default_bar()
             .template("[{elapsed_precise}] [{bar:.cyan/blue}] {pos}/{len} ({eta}) {wide_msg}")
             .progress_chars("#>-"),
     );
-    for files in duplicate_sizes {
-        progress_bar.inc(1);
-        progress_bar.set_message(format!("{}", files[0].path().display()));
-        match verify_duplicate(&files) {
-            IsDuplicate::No => info!("Skipping dedupe due to file mismatch"),
+    let progress_count = AtomicUsize::new(0);
+    let verdicts: Vec<(Vec<DirEntry>, IsDuplicate)> = candidate_groups
+        .into_par_iter()
+        .flat_map(|files| {
+            progress_bar.set_position(progress_count.fetch_add(1, Ordering::SeqCst) as u64 + 1);
+            progress_bar.set_message(format!("{}", files[0].path().display()));
+            verify_duplicate(files, hash_type, &hash_cache)
+        })
+        .collect();
+
+    if opt.json {
+        let report: Vec<DuplicateGroupReport> = verdicts
+            .iter()
+            .map(|(files, confidence)| DuplicateGroupReport {
+                paths: files.iter().map(|f| f.path().to_path_buf()).collect(),
+                size: files[0].metadata().unwrap().len(),
+                inode: files[0].ino(),
+                guessed_metadata: guess_metadata(files[0].path()),
+                confidence: *confidence,
+            })
+            .collect();
+        hash_cache.save();
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+
+    for (files, verdict) in verdicts {
+        match verdict {
             IsDuplicate::VeryLikely => {
                 info!("Very likely duplicates: ");
                 for file in files.iter() {
                     info!("\t{}", file.path().display());
                 }
-                files_to_hardlink.push(files);
+                files_to_dedupe.push(files);
             }
             IsDuplicate::Maybe => {
                 info!("Maybe duplicates: ");
@@ -141,7 +584,7 @@ fn main() {
     }
     progress_bar.finish_with_message(format!(
         "Very likely duplicates: {}; Questionable duplicates: {}",
-        files_to_hardlink.len(),
+        files_to_dedupe.len(),
         files_for_manual_confirmation.len()
     ));
 
@@ -150,39 +593,59 @@ fn main() {
         Full,
         HundredMB,
     }
-    let mut idx = 0;
-    let len = files_for_manual_confirmation.len();
-    for files in files_for_manual_confirmation {
-        println!(
-            "What do you want to do with these potential duplicates? ({} / {})",
-            idx, len
-        );
-        idx += 1;
-        for file in files.iter() {
-            println!("\t{}", file.path().display());
-        }
-
-        let options = vec!["skip", "hash 100MB", "hash full", "mark as dupe"];
-        let selection = match Select::with_theme(&ColorfulTheme::default())
-            .items(&options)
-            .default(0)
-            .interact_opt()
-        {
-            Ok(sel) => sel,
-            Err(e) => {
-                error!("Error getting input: {:?}", e);
-                continue;
+    if opt.non_interactive {
+        let confidence_threshold = opt.confidence_threshold;
+        for files in files_for_manual_confirmation {
+            if IsDuplicate::Maybe.rank() >= confidence_threshold.rank() {
+                // A Maybe group's only evidence is a shared size and a 10MB hash
+                // match despite guessed metadata that disagrees; that's not
+                // enough to auto-act on unattended, so route it through the same
+                // full-file hash pass `hash full` performs interactively, and
+                // only act on whatever subgroups still match afterwards.
+                files_to_hash.push((HashAmount::Full, files));
+            } else {
+                info!(
+                    "Skipping group below --confidence-threshold {}: {}",
+                    confidence_threshold,
+                    files[0].path().display()
+                );
+            }
+        }
+    } else {
+        let mut idx = 0;
+        let len = files_for_manual_confirmation.len();
+        for files in files_for_manual_confirmation {
+            println!(
+                "What do you want to do with these potential duplicates? ({} / {})",
+                idx, len
+            );
+            idx += 1;
+            for file in files.iter() {
+                println!("\t{}", file.path().display());
+            }
+
+            let options = vec!["skip", "hash 100MB", "hash full", "mark as dupe"];
+            let selection = match Select::with_theme(&ColorfulTheme::default())
+                .items(&options)
+                .default(0)
+                .interact_opt()
+            {
+                Ok(sel) => sel,
+                Err(e) => {
+                    error!("Error getting input: {:?}", e);
+                    continue;
+                }
+            };
+            match selection {
+                Some(index) => match options[index] {
+                    "skip" => {}
+                    "hash 100MB" => files_to_hash.push((HashAmount::HundredMB, files)),
+                    "hash full" => files_to_hash.push((HashAmount::Full, files)),
+                    "mark as dupe" => files_to_dedupe.push(files),
+                    _ => error!("Unexpected input"),
+                },
+                None => println!("User did not select anything, skipping"),
             }
-        };
-        match selection {
-            Some(index) => match options[index] {
-                "skip" => {}
-                "hash 100MB" => files_to_hash.push((HashAmount::HundredMB, files)),
-                "hash full" => files_to_hash.push((HashAmount::Full, files)),
-                "mark as dupe" => files_to_hardlink.push(files),
-                _ => error!("Unexpected input"),
-            },
-            None => println!("User did not select anything, skipping"),
         }
     }
 
@@ -193,64 +656,52 @@ fn main() {
             .template("[{elapsed_precise}] [{bar:.cyan/blue}] {pos}/{len} ({eta}) {wide_msg}")
             .progress_chars("#>-"),
     );
-    for (hash_amt, files) in files_to_hash {
-        progress_bar.set_message(format!(
-            "{}: {}",
-            match hash_amt {
-                HashAmount::Full => "full hash",
-                HashAmount::HundredMB => "first 100MB",
-            },
-            files[0].path().display()
-        ));
-        info!("Calculating hashes for:");
-        for file in files.iter() {
-            info!("\t{}", file.path().display());
-        }
-        let all_hashes_match = files.windows(2).all(|w| match hash_amt {
-            HashAmount::Full => full_hashes_match(w[0].path(), w[1].path()),
-            HashAmount::HundredMB => partial_hashes_match(w[0].path(), w[1].path(), 100),
-        });
-        if all_hashes_match {
-            files_to_hardlink.push(files);
-        } else {
-            warn!("Hashes differ!");
-            progress_bar.println(format!("Hashes differed for {}", files[0].path().display()));
+    let progress_count = AtomicUsize::new(0);
+    let hash_results: Vec<(usize, String, Vec<Vec<DirEntry>>)> = files_to_hash
+        .into_par_iter()
+        .map(|(hash_amt, files)| {
+            progress_bar.set_message(format!(
+                "{}: {}",
+                match hash_amt {
+                    HashAmount::Full => "full hash",
+                    HashAmount::HundredMB => "first 100MB",
+                },
+                files[0].path().display()
+            ));
+            info!("Calculating hashes for:");
             for file in files.iter() {
-                warn!("\t{}", file.path().display());
+                info!("\t{}", file.path().display());
             }
+            let original_count = files.len();
+            let first_path = format!("{}", files[0].path().display());
+            let megabytes = match hash_amt {
+                HashAmount::Full => None,
+                HashAmount::HundredMB => Some(100),
+            };
+            let subgroups = group_by_hash(files, megabytes, hash_type, &hash_cache);
+            progress_bar.set_position(progress_count.fetch_add(1, Ordering::SeqCst) as u64 + 1);
+            (original_count, first_path, subgroups)
+        })
+        .collect();
+    for (original_count, first_path, subgroups) in hash_results {
+        let grouped_count: usize = subgroups.iter().map(|g| g.len()).sum();
+        if grouped_count < original_count {
+            warn!("Hashes differ!");
+            progress_bar.println(format!("Hashes differed for {}", first_path));
+        }
+        for group in subgroups {
+            files_to_dedupe.push(group);
         }
-        progress_bar.inc(1);
     }
     progress_bar.finish_with_message(format!("Finished hashing files"));
 
-    let answer = Question::new(&format!(
-        "Are all writing programs stopped? Do you want to hardlink {} files?",
-        files_to_hardlink.len()
-    ))
-    .yes_no()
-    .until_acceptable()
-    .confirm();
-
-    if answer == Answer::YES {
-        println!("Applying hardlinks");
-        let progress_bar = ProgressBar::new(files_to_hardlink.len().try_into().unwrap());
-        progress_bar.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] [{bar:.cyan/blue}] {pos}/{len} ({eta}) {wide_msg}")
-                .progress_chars("#>-"),
-        );
-        for files in files_to_hardlink {
-            progress_bar.inc(1);
-            progress_bar.set_message(format!("{}", files[0].path().display()));
-            hardlink(
-                files
-                    .into_iter()
-                    .map(move |f| f.path().to_path_buf())
-                    .collect(),
-            );
-        }
-        progress_bar.finish_with_message(format!("Finished hardlinking files"));
-    }
+    let files_to_dedupe: Vec<Vec<PathBuf>> = files_to_dedupe
+        .into_iter()
+        .map(|files| files.into_iter().map(|f| f.path().to_path_buf()).collect())
+        .collect();
+    confirm_and_apply(files_to_dedupe, action, opt.non_interactive);
+
+    hash_cache.save();
 
     println!("Total files scanned: {}", total_file_count);
     println!(
@@ -259,15 +710,173 @@ fn main() {
     );
 }
 
-fn hardlink(paths: Vec<PathBuf>) {
+/// Reads a `--json` report back in and acts directly on its groups, skipping
+/// the scan and hash passes that would normally produce them. Groups below
+/// `confidence_threshold` are skipped just like the live scan's non-interactive
+/// path, and `Maybe` groups are re-verified with a full-file hash before being
+/// acted on, since the report's only evidence for them is a 10MB-prefix match
+/// against guessed metadata that disagreed.
+fn apply_from_report(
+    report_path: &Path,
+    action: Action,
+    confidence_threshold: IsDuplicate,
+    hash_type: HashType,
+    hash_cache: &HashCache,
+    non_interactive: bool,
+) {
+    let contents = fs::read_to_string(report_path)
+        .unwrap_or_else(|e| panic!("Couldn't read report {:?}: {}", report_path, e));
+    let report: Vec<DuplicateGroupReport> = serde_json::from_str(&contents)
+        .unwrap_or_else(|e| panic!("Couldn't parse report {:?}: {}", report_path, e));
+
+    let mut files_to_dedupe = vec![];
+    for group in report {
+        if group.confidence.rank() < confidence_threshold.rank() {
+            info!(
+                "Skipping group below --confidence-threshold {}: {}",
+                confidence_threshold,
+                group.paths[0].display()
+            );
+            continue;
+        }
+        match group.confidence {
+            IsDuplicate::VeryLikely => files_to_dedupe.push(group.paths),
+            IsDuplicate::Maybe => {
+                for subgroup in group_paths_by_hash(group.paths, hash_type, hash_cache) {
+                    files_to_dedupe.push(subgroup);
+                }
+            }
+        }
+    }
+
+    println!(
+        "Loaded {} duplicate groups from {}",
+        files_to_dedupe.len(),
+        report_path.display()
+    );
+    confirm_and_apply(files_to_dedupe, action, non_interactive);
+}
+
+/// Like `group_by_hash`, but for paths read back from a `--from-report` file
+/// rather than fresh `DirEntry`s from a scan. Only a full-file hash is needed
+/// here, so unlike `group_by_hash` there's no `megabytes` prehash depth.
+fn group_paths_by_hash(paths: Vec<PathBuf>, hash_type: HashType, hash_cache: &HashCache) -> Vec<Vec<PathBuf>> {
+    let mut buckets: HashMap<Vec<u8>, Vec<PathBuf>> = HashMap::new();
+    for path in paths {
+        match generate_full_hash(&path, hash_type, hash_cache) {
+            Ok(hash) => buckets.entry(hash).or_insert_with(Vec::new).push(path),
+            Err(e) => warn!("Couldn't hash {}: {}", path.display(), e),
+        }
+    }
+    buckets.into_values().filter(|group| group.len() >= 2).collect()
+}
+
+/// Prompts for confirmation (skipped when `non_interactive`) and, if the user
+/// proceeds, applies `action` to every group. Shared by the normal scan path
+/// and `--from-report`, which reach this with groups built two different ways.
+fn confirm_and_apply(files_to_dedupe: Vec<Vec<PathBuf>>, action: Action, non_interactive: bool) {
+    let proceed = non_interactive
+        || Question::new(&format!(
+            "Are all writing programs stopped? Do you want to {} {} groups of duplicate files?",
+            action.verb(),
+            files_to_dedupe.len()
+        ))
+        .yes_no()
+        .until_acceptable()
+        .confirm()
+            == Answer::YES;
+
+    if !proceed {
+        return;
+    }
+
+    println!("Applying {} action", action.verb());
+    let progress_bar = ProgressBar::new(files_to_dedupe.len().try_into().unwrap());
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] [{bar:.cyan/blue}] {pos}/{len} ({eta}) {wide_msg}")
+            .progress_chars("#>-"),
+    );
+    let mut action_bytes_reclaimed = 0;
+    for paths in files_to_dedupe {
+        progress_bar.inc(1);
+        progress_bar.set_message(format!("{}", paths[0].display()));
+        action_bytes_reclaimed += apply_action(paths, action);
+    }
+    progress_bar.finish_with_message(format!("Finished applying {} action", action.verb()));
+    println!(
+        "Reclaimed {} GB via {}",
+        action_bytes_reclaimed / 1024 / 1024 / 1024,
+        action.verb()
+    );
+}
+
+/// Applies `action` to every file in `paths` except the first, which is kept
+/// untouched as the copy the others are resolved against. Returns the number
+/// of bytes reclaimed, i.e. the on-disk size of the files that were removed
+/// or turned into links.
+///
+/// For link/clone actions, the replacement is built at a temporary path next to
+/// `other` and only swapped in once that succeeds; `other` itself is never
+/// removed until its replacement is confirmed to exist, so a failure partway
+/// through (e.g. `--action reflink` on a filesystem without copy-on-write
+/// support) can't destroy data or abort the run with files left unprocessed.
+fn apply_action(paths: Vec<PathBuf>, action: Action) -> u64 {
     let mut paths = paths.into_iter();
     let path = paths.next().unwrap();
+    let mut bytes_reclaimed = 0;
     for other in paths {
-        info!("Unlink  {}", other.display());
-        fs::remove_file(other.clone()).unwrap();
-        info!("Link to {}", path.display());
-        fs::hard_link(path.clone(), other.clone()).unwrap();
+        let other_size = fs::metadata(&other).map(|m| m.len()).unwrap_or(0);
+
+        if let Action::Delete = action {
+            info!("Removing {}", other.display());
+            match fs::remove_file(&other) {
+                Ok(()) => bytes_reclaimed += other_size,
+                Err(e) => warn!("Couldn't remove {}: {}", other.display(), e),
+            }
+            continue;
+        }
+
+        let tmp_name = format!(
+            "{}.dedupe-tmp",
+            other.file_name().and_then(|n| n.to_str()).unwrap_or("dedupe")
+        );
+        let tmp = other.with_file_name(tmp_name);
+        let create_result = match action {
+            Action::Hardlink => fs::hard_link(&path, &tmp),
+            Action::Reflink => reflink::reflink(&path, &tmp),
+            // A relative `path` (e.g. "./media" from --directories) would resolve
+            // against the symlink's own directory rather than the CWD it was
+            // created from, silently breaking the link whenever the kept file and
+            // the duplicate live in different directories. Canonicalize first.
+            Action::Symlink => fs::canonicalize(&path).and_then(|target| symlink(&target, &tmp)),
+            Action::Delete => unreachable!(),
+        };
+        if let Err(e) = create_result {
+            warn!(
+                "Couldn't {} {} from {}, leaving it untouched: {}",
+                action.verb(),
+                other.display(),
+                path.display(),
+                e
+            );
+            let _ = fs::remove_file(&tmp);
+            continue;
+        }
+
+        if let Err(e) = fs::remove_file(&other) {
+            warn!("Couldn't remove {} to replace it: {}", other.display(), e);
+            let _ = fs::remove_file(&tmp);
+            continue;
+        }
+        if let Err(e) = fs::rename(&tmp, &other) {
+            warn!("Couldn't move replacement into place for {}: {}", other.display(), e);
+            continue;
+        }
+        info!("{} {} from {}", action.verb(), other.display(), path.display());
+        bytes_reclaimed += other_size;
     }
+    bytes_reclaimed
 }
 
 fn is_paw_patrol_bar_rescue(path: &Path) -> bool {
@@ -276,13 +885,44 @@ fn is_paw_patrol_bar_rescue(path: &Path) -> bool {
     }
     RE.is_match(path.file_stem().unwrap().to_str().unwrap())
 }
-enum IsDuplicate {
-    VeryLikely,
-    Maybe,
-    No,
+
+/// Groups files that already share a size into hash-equivalence classes,
+/// keyed by the hash computed at the requested depth. Only groups with at
+/// least 2 members are returned, so files that already diverged at this
+/// depth are dropped without ever touching the next, more expensive pass.
+fn group_by_hash(
+    files: Vec<DirEntry>,
+    megabytes: Option<usize>,
+    hash_type: HashType,
+    hash_cache: &HashCache,
+) -> Vec<Vec<DirEntry>> {
+    let mut buckets: HashMap<Vec<u8>, Vec<DirEntry>> = HashMap::new();
+    for file in files {
+        let hash = match megabytes {
+            Some(megabytes) => generate_partial_hash(file.path(), megabytes, hash_type, hash_cache),
+            None => generate_full_hash(file.path(), hash_type, hash_cache),
+        };
+        match hash {
+            Ok(hash) => buckets.entry(hash).or_insert_with(Vec::new).push(file),
+            Err(e) => warn!("Couldn't hash {}: {}", file.path().display(), e),
+        }
+    }
+    buckets.into_values().filter(|group| group.len() >= 2).collect()
 }
 
-fn verify_duplicate(files: &[DirEntry]) -> IsDuplicate {
+/// Decides whether a group of files that already share a size and a 1MB
+/// prehash is a confident duplicate or one that needs a deeper hash (and
+/// possibly a human) to confirm, based on how well their filenames agree.
+/// When the guessed metadata disagrees, a 1MB prehash match alone isn't
+/// enough evidence, so this re-checks a deeper 10MB hash and splits the
+/// group along it: subgroups that still agree come back as `Maybe`, and
+/// anything that no longer matches at 10MB is dropped as not a duplicate
+/// at all rather than carried forward as unverified.
+fn verify_duplicate(
+    files: Vec<DirEntry>,
+    hash_type: HashType,
+    hash_cache: &HashCache,
+) -> Vec<(Vec<DirEntry>, IsDuplicate)> {
     let mut guessed_metadata_differs = false;
 
     if let Some(_air_date) = generate_probable_air_date(files[0].path()) {
@@ -291,7 +931,7 @@ fn verify_duplicate(files: &[DirEntry]) -> IsDuplicate {
         });
         if !all_dates_match {
             debug!("Differing air dates guessed!");
-            for file in files {
+            for file in &files {
                 debug!("\t{:?}", generate_probable_air_date(file.path()));
                 debug!("\t\t{}", file.path().display());
             }
@@ -303,7 +943,7 @@ fn verify_duplicate(files: &[DirEntry]) -> IsDuplicate {
         });
         if (!all_episodes_match) && files.iter().all(|w| !is_paw_patrol_bar_rescue(w.path())) {
             debug!("Differing episodes guessed!");
-            for file in files {
+            for file in &files {
                 debug!("\t{:?}", generate_probable_episode(file.path()));
                 debug!("\t\t{:?}", file.path().display());
             }
@@ -315,7 +955,7 @@ fn verify_duplicate(files: &[DirEntry]) -> IsDuplicate {
             .all(|w| generate_probable_name(w[0].path()) == generate_probable_name(w[1].path()));
         if !all_titles_match {
             debug!("Differing titles guessed!");
-            for file in files {
+            for file in &files {
                 debug!("\t{}", generate_probable_name(file.path()));
                 debug!("\t\t{}", file.path().display());
             }
@@ -325,78 +965,90 @@ fn verify_duplicate(files: &[DirEntry]) -> IsDuplicate {
 
     if files.len() > 2 {
         debug!("More than 2 files at this size!");
-        for file in files {
+        for file in &files {
             debug!("\t\t{}", file.path().display());
         }
     }
 
-    // Always check the partial hashes for 1MB, it's cheap
-    match files
-        .windows(2)
-        .all(|w| partial_hashes_match(w[0].path(), w[1].path(), 1))
-    {
-        true => {
-            if guessed_metadata_differs {
-                // Check the first 10MB, that should get us past any false positive
-                match files
-                    .windows(2)
-                    .all(|w| partial_hashes_match(w[0].path(), w[1].path(), 10))
-                {
-                    true => IsDuplicate::Maybe,
-                    false => IsDuplicate::No,
-                }
-            } else {
-                IsDuplicate::VeryLikely
-            }
-        }
-        false => {
-            if !guessed_metadata_differs {
-                warn!("Didn't detect differing titles");
-                for file in files {
-                    warn!("\t\t{}", file.path().display());
-                }
-            }
-            IsDuplicate::No
-        }
+    if !guessed_metadata_differs {
+        return vec![(files, IsDuplicate::VeryLikely)];
     }
-}
 
-fn partial_hashes_match(path1: &Path, path2: &Path, megabytes: usize) -> bool {
-    if let Ok(partial_hash1) = generate_partial_hash(path1, megabytes) {
-        if let Ok(partial_hash2) = generate_partial_hash(path2, megabytes) {
-            return partial_hash1 == partial_hash2;
-        }
-    }
-    return false;
+    // Guessed metadata disagrees, so get past any false positive with a deeper
+    // hash before trusting it at all. Subgroups that still match at 10MB are
+    // Maybe; anything that splits off no longer matches and isn't a duplicate.
+    group_by_hash(files, Some(10), hash_type, hash_cache)
+        .into_iter()
+        .map(|group| (group, IsDuplicate::Maybe))
+        .collect()
 }
 
-fn full_hashes_match(path1: &Path, path2: &Path) -> bool {
-    if let Ok(hash1) = generate_full_hash(path1) {
-        if let Ok(hash2) = generate_full_hash(path2) {
-            return hash1 == hash2;
+fn generate_hash(reader: &mut impl io::Read, hash_type: HashType) -> Result<Vec<u8>, Error> {
+    let mut hasher = hash_type.hasher();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
         }
+        hasher.update(&buffer[..n]);
     }
-    return false;
+    Ok(hasher.finalize())
 }
 
-fn generate_hash(mut reader: &mut impl io::Read) -> Result<Vec<u8>, Error> {
-    let mut hasher = Blake2b::new();
-    let _n = io::copy(&mut reader, &mut hasher)?;
-    let hash = hasher.result();
-    Ok(hash.to_vec())
-}
-
-fn generate_partial_hash(path: &Path, megabytes: usize) -> Result<Vec<u8>, Error> {
+fn generate_partial_hash(
+    path: &Path,
+    megabytes: usize,
+    hash_type: HashType,
+    hash_cache: &HashCache,
+) -> Result<Vec<u8>, Error> {
+    // Canonicalize before touching the cache: two scans of the same file via a
+    // different relative path, CWD, or symlinked mount would otherwise look like
+    // two different cache keys and never hit.
+    let path = fs::canonicalize(path)?;
+    let path = path.as_path();
+    let metadata = fs::metadata(path)?;
+    let size = metadata.len();
+    let mtime = mtime_secs(&metadata)?;
+    if let Some(hash) = hash_cache.get_partial(path, megabytes, size, mtime, hash_type) {
+        trace!("Partial hash cache hit for {}", path.display());
+        return Ok(hash);
+    }
     const ONE_MEGABYTE: usize = 1024 * 1024;
     let mut file = fs::File::open(&path)?;
     let mut buffer = vec![0; ONE_MEGABYTE * megabytes];
     file.read(&mut buffer)?;
-    generate_hash(&mut &buffer[..])
+    let hash = generate_hash(&mut &buffer[..], hash_type)?;
+    hash_cache.put_partial(path, megabytes, size, mtime, hash_type, hash.clone());
+    Ok(hash)
 }
 
-fn generate_full_hash(path: &Path) -> Result<Vec<u8>, Error> {
+fn generate_full_hash(path: &Path, hash_type: HashType, hash_cache: &HashCache) -> Result<Vec<u8>, Error> {
+    let path = fs::canonicalize(path)?;
+    let path = path.as_path();
+    let metadata = fs::metadata(path)?;
+    let size = metadata.len();
+    let mtime = mtime_secs(&metadata)?;
+    if let Some(hash) = hash_cache.get_full(path, size, mtime, hash_type) {
+        trace!("Full hash cache hit for {}", path.display());
+        return Ok(hash);
+    }
     let mut file = fs::File::open(&path)?;
-    generate_hash(&mut file)
+    let hash = generate_hash(&mut file, hash_type)?;
+    hash_cache.put_full(path, size, mtime, hash_type, hash.clone());
+    Ok(hash)
+}
+
+/// Whichever of the three heuristics `verify_duplicate` uses actually applies
+/// to this path, for display in the `--json` report.
+fn guess_metadata(path: &Path) -> String {
+    if let Some(air_date) = generate_probable_air_date(path) {
+        return air_date;
+    }
+    if let Some(episode) = generate_probable_episode(path) {
+        return episode;
+    }
+    generate_probable_name(path)
 }
 
 fn generate_probable_name(path: &Path) -> String {
@@ -440,7 +1092,29 @@ fn generate_probable_air_date(path: &Path) -> Option<String> {
     date_guess
 }
 
-fn walk_directory(path: PathBuf, min_filesize_mb: u64) -> impl Iterator<Item = DirEntry> {
+/// Suffixes used by browsers/downloaders for files that are still being
+/// written; these are never real media and hashing them is wasted work.
+const PARTIAL_DOWNLOAD_SUFFIXES: &[&str] = &[".part", ".partial", ".crdownload", ".download", ".tmp"];
+
+fn is_partial_download(entry: &DirEntry) -> bool {
+    entry
+        .file_name()
+        .to_str()
+        .map(|name| {
+            PARTIAL_DOWNLOAD_SUFFIXES
+                .iter()
+                .any(|suffix| name.ends_with(suffix))
+        })
+        .unwrap_or(false)
+}
+
+fn walk_directory<'a>(
+    path: PathBuf,
+    min_filesize_mb: u64,
+    include_ext: &'a [String],
+    exclude_ext: &'a [String],
+    exclude_path: &'a [glob::Pattern],
+) -> impl Iterator<Item = DirEntry> + 'a {
     fn is_hidden(entry: &DirEntry) -> bool {
         entry
             .file_name()
@@ -457,6 +1131,27 @@ fn walk_directory(path: PathBuf, min_filesize_mb: u64) -> impl Iterator<Item = D
         if !metadata.is_file() {
             return None;
         }
+        if is_partial_download(&entry) {
+            trace!("Skipping partial download: {}", entry.path().display());
+            return None;
+        }
+        if exclude_path.iter().any(|pattern| pattern.matches_path(entry.path())) {
+            trace!("Skipping excluded path: {}", entry.path().display());
+            return None;
+        }
+        let extension = entry
+            .path()
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("");
+        if !include_ext.is_empty() && !include_ext.iter().any(|e| e.eq_ignore_ascii_case(extension)) {
+            trace!("Skipping non-included extension: {}", entry.path().display());
+            return None;
+        }
+        if exclude_ext.iter().any(|e| e.eq_ignore_ascii_case(extension)) {
+            trace!("Skipping excluded extension: {}", entry.path().display());
+            return None;
+        }
         let filesize = metadata.len();
         if (filesize / 1024 / 1024) < min_filesize_mb {
             trace!(